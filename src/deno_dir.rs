@@ -5,7 +5,11 @@ use fs as deno_fs;
 use hyper::Uri;
 use net;
 use ring;
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+use serde_json;
 use std;
+use std::collections::HashMap;
 use std::fmt::Write;
 use std::fs;
 use std::io::Error;
@@ -28,6 +32,63 @@ pub struct DenoDir {
   pub deps: PathBuf,
   // If remote resources should be reloaded.
   reload: bool,
+  // Path to a deno.lock-style JSON file mapping resolved remote module URL
+  // to the expected hex-encoded SHA256 digest of its source. `None`
+  // disables subresource integrity checking entirely.
+  lockfile: Option<PathBuf>,
+  // When true, a URL missing from the lockfile has its digest recorded
+  // rather than rejected. A lockfile used without this flag set verifies
+  // but never grows.
+  lock_write: bool,
+  // A WICG-style import map (https://github.com/WICG/import-maps), loaded
+  // once at construction. `resolve_module` consults this before falling
+  // back to its normal URL/path resolution, so bare specifiers like
+  // `"std/http"` can be rewritten to a pinned target.
+  import_map: Option<ImportMap>,
+  // If true, `fetch_remote_source` never hits the network: a remote
+  // module not already cached under `deps/` is a hard error rather than
+  // a download. Parallel to `reload`, but pulls the other direction --
+  // this supports reproducible, network-free CI/offline builds.
+  cached_only: bool,
+}
+
+#[derive(Debug, Clone)]
+struct ImportMap {
+  imports: HashMap<String, String>,
+}
+
+impl ImportMap {
+  fn load(path: &Path) -> std::io::Result<ImportMap> {
+    let contents = fs::read_to_string(path)?;
+    let parsed: serde_json::Value = serde_json::from_str(&contents)
+      .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?;
+    let mut imports = HashMap::new();
+    if let Some(map) = parsed.get("imports").and_then(|v| v.as_object()) {
+      for (key, value) in map.iter() {
+        if let Some(target) = value.as_str() {
+          imports.insert(key.clone(), target.to_string());
+        }
+      }
+    }
+    Ok(ImportMap { imports })
+  }
+
+  // Resolves `specifier` against this map, preferring an exact match and
+  // otherwise the longest `trailing-slash` prefix match, per the WICG
+  // import maps spec. Returns `None` when nothing in the map applies, in
+  // which case the caller should fall back to normal resolution.
+  fn resolve(self: &ImportMap, specifier: &str) -> Option<String> {
+    if let Some(target) = self.imports.get(specifier) {
+      return Some(target.clone());
+    }
+
+    self
+      .imports
+      .iter()
+      .filter(|(key, _)| key.ends_with('/') && specifier.starts_with(key.as_str()))
+      .max_by_key(|(key, _)| key.len())
+      .map(|(key, target)| target.clone() + &specifier[key.len()..])
+  }
 }
 
 #[derive(Debug, PartialEq)]
@@ -57,6 +118,10 @@ impl DenoDir {
   pub fn new(
     reload: bool,
     custom_root: Option<&Path>,
+    lockfile: Option<&Path>,
+    lock_write: bool,
+    import_map_path: Option<&Path>,
+    cached_only: bool,
   ) -> std::io::Result<DenoDir> {
     // Only setup once.
     let home_dir = std::env::home_dir().expect("Could not get home directory.");
@@ -69,11 +134,20 @@ impl DenoDir {
     let gen = root.as_path().join("gen");
     let deps = root.as_path().join("deps");
 
+    let import_map = match import_map_path {
+      Some(path) => Some(ImportMap::load(path)?),
+      None => None,
+    };
+
     let deno_dir = DenoDir {
       root,
       gen,
       deps,
       reload,
+      lockfile: lockfile.map(|p| p.to_path_buf()),
+      lock_write,
+      import_map,
+      cached_only,
     };
     deno_fs::mkdir(deno_dir.gen.as_ref())?;
     deno_fs::mkdir(deno_dir.deps.as_ref())?;
@@ -112,51 +186,135 @@ impl DenoDir {
     output_code: &str,
   ) -> std::io::Result<()> {
     let cache_path = self.cache_path(filename, source_code);
-    // TODO(ry) This is a race condition w.r.t to exists() -- probably should
-    // create the file in exclusive mode. A worry is what might happen is there
-    // are two processes and one reads the cache file while the other is in the
-    // midst of writing it.
-    if cache_path.exists() {
-      Ok(())
-    } else {
-      fs::write(cache_path, output_code.as_bytes())
-    }
+    write_atomic(&cache_path, output_code.as_bytes())
   }
 
   // Prototype https://github.com/denoland/deno/blob/golang/deno_dir.go#L37-L73
+  //
+  // Returns the source body along with the canonical URL it should be
+  // treated as having come from: when the sidecar metadata records that
+  // `module_name` redirected elsewhere, that final URL is returned instead,
+  // so relative imports inside the module resolve against its real
+  // location rather than the pre-redirect one.
   fn fetch_remote_source(
     self: &DenoDir,
     module_name: &Uri,
     filename: &str,
-  ) -> DenoResult<String> {
+  ) -> DenoResult<(String, Uri)> {
     let p = Path::new(filename);
+    let metadata_file = metadata_path(p);
 
-    let src = if self.reload || !p.exists() {
-      println!("Downloading {}", module_name);
-      let source = net::fetch_sync_string(module_name)?;
-      match p.parent() {
-        Some(ref parent) => fs::create_dir_all(parent),
-        None => Ok(()),
-      }?;
-      deno_fs::write_file_sync(&p, source.as_bytes())?;
-      source
-    } else {
+    if self.cached_only {
+      if !p.exists() {
+        return Err(deno_error(format!(
+          "cannot fetch {} in cached-only mode: not already present in the cache",
+          module_name
+        )));
+      }
+      // Offline mode never revalidates either -- a cached entry is used
+      // as-is regardless of any recorded freshness lifetime.
       let source = fs::read_to_string(&p)?;
-      source
+      let canonical = read_metadata(&metadata_file)
+        .map(|metadata| canonical_url(&metadata, module_name))
+        .unwrap_or_else(|_| module_name.clone());
+      return Ok((source, canonical));
+    }
+
+    if self.reload || !p.exists() {
+      println!("Downloading {}", module_name);
+      let fetch_result = net::fetch_sync_string(module_name)?;
+      self.store_fetch_result(module_name, &p, &metadata_file, &fetch_result)?;
+      let canonical = fetch_result.url.clone();
+      return Ok((fetch_result.body, canonical));
+    }
+
+    // No recorded freshness lifetime (e.g. the entry predates this
+    // metadata sidecar, or the server sent no `Cache-Control`) means we
+    // have nothing to revalidate against, so fall back to trusting the
+    // cache as-is, same as before conditional revalidation existed.
+    let metadata = match read_metadata(&metadata_file) {
+      Ok(metadata) => metadata,
+      Err(_) => return Ok((fs::read_to_string(&p)?, module_name.clone())),
     };
-    Ok(src)
+
+    if !metadata.is_stale() {
+      let canonical = canonical_url(&metadata, module_name);
+      return Ok((fs::read_to_string(&p)?, canonical));
+    }
+
+    debug!("Revalidating {}", module_name);
+    let validators = net::Validators {
+      etag: metadata.etag.clone(),
+      last_modified: metadata.last_modified.clone(),
+    };
+    let fetch_result = net::fetch_sync_string_conditional(module_name, &validators)?;
+
+    if fetch_result.status == hyper::StatusCode::NOT_MODIFIED {
+      // The cached body is still good; just bump the freshness timestamp
+      // so we don't revalidate again until the next max-age window.
+      write_metadata(&metadata_file, &metadata.refreshed())?;
+      let canonical = canonical_url(&metadata, module_name);
+      return Ok((fs::read_to_string(&p)?, canonical));
+    }
+
+    self.store_fetch_result(module_name, &p, &metadata_file, &fetch_result)?;
+    let canonical = fetch_result.url.clone();
+    Ok((fetch_result.body, canonical))
+  }
+
+  // Persists a freshly (re)fetched remote source and its cache metadata.
+  // If the fetch was redirected, the body and metadata are also mirrored
+  // under the final URL's own cache path, so a later import of that URL
+  // hits this cache entry directly.
+  fn store_fetch_result(
+    self: &DenoDir,
+    requested_url: &Uri,
+    p: &Path,
+    metadata_file: &Path,
+    fetch_result: &net::FetchResult,
+  ) -> DenoResult<()> {
+    match p.parent() {
+      Some(ref parent) => fs::create_dir_all(parent),
+      None => Ok(()),
+    }?;
+    write_atomic(p, fetch_result.body.as_bytes())?;
+    write_metadata(
+      metadata_file,
+      &CacheMetadata::from_fetch(requested_url, fetch_result),
+    )?;
+
+    if fetch_result.url != *requested_url {
+      let final_path = get_cache_filename(&self.deps, fetch_result.url.clone());
+      if let Some(ref parent) = final_path.parent() {
+        fs::create_dir_all(parent)?;
+      }
+      write_atomic(&final_path, fetch_result.body.as_bytes())?;
+      write_metadata(
+        &metadata_path(&final_path),
+        &CacheMetadata::from_fetch(&fetch_result.url, fetch_result),
+      )?;
+    }
+
+    Ok(())
   }
 
   // Prototype: https://github.com/denoland/deno/blob/golang/os.go#L122-L138
+  //
+  // Returns the source alongside the canonical module name it was served
+  // as. For a remote module that redirected, this is the final URL rather
+  // than `module_name`, so the caller resolves that module's own relative
+  // imports against where it actually lives.
   fn get_source_code(
     self: &DenoDir,
     module_name: &ModuleLocation,
     filename: &Path,
-  ) -> DenoResult<String> {
+  ) -> DenoResult<(String, String)> {
     if is_remote(&module_name) {
       if let ModuleLocation::Url(url) = module_name {
-        self
-          .fetch_remote_source(url, deno_fs::normalize_path(filename).as_str())
+        let (source, canonical_url) = self
+          .fetch_remote_source(url, deno_fs::normalize_path(filename).as_str())?;
+        self.check_source_integrity(url, &source)?;
+        Ok((source, canonical_url.to_string()))
       } else {
         panic!("Remote code execution require an URL")
       }
@@ -168,7 +326,7 @@ impl DenoDir {
         "if a module isn't remote, it should have the same filename"
       );
       let src = fs::read_to_string(filename)?;
-      Ok(src)
+      Ok((src, module_name.to_string()))
     }
   }
 
@@ -189,9 +347,9 @@ impl DenoDir {
 
     let out = self
       .get_source_code(&module_name, filepath.as_path())
-      .and_then(|source_code| {
+      .and_then(|(source_code, canonical_module_name)| {
         Ok(CodeFetchOutput {
-          module_name: module_name.to_string(),
+          module_name: canonical_module_name,
           filename,
           source_code,
           maybe_output_code: None,
@@ -244,6 +402,19 @@ impl DenoDir {
       module_specifier, containing_file
     );
 
+    // Bare specifiers and prefix aliases go through the import map, if
+    // configured, before any other resolution happens. The rewritten
+    // target may itself be a remote URL or a local path, so it continues
+    // through the same logic below relative to `containing_file`.
+    let mapped_specifier = self
+      .import_map
+      .as_ref()
+      .and_then(|map| map.resolve(module_specifier));
+    let module_specifier = mapped_specifier
+      .as_ref()
+      .map(|s| s.as_str())
+      .unwrap_or(module_specifier);
+
     let r = module_specifier.parse::<Uri>();
     let is_remote_url = match module_specifier.parse::<Uri>() {
       Ok(uri) => match uri.scheme_part() {
@@ -283,8 +454,95 @@ impl DenoDir {
     );
     Ok((module_name, filename))
   }
+
+  // Verifies `source_code` fetched from `url` against the digest recorded
+  // for it in `self.lockfile`, if one is configured. With `lock_write` set,
+  // a URL missing from the lockfile has its digest recorded; otherwise a
+  // missing or mismatched digest is an error, so a tampered or unpinned
+  // remote dependency can never silently execute.
+  fn check_source_integrity(
+    self: &DenoDir,
+    url: &Uri,
+    source_code: &str,
+  ) -> DenoResult<()> {
+    let lockfile = match &self.lockfile {
+      Some(path) => path,
+      None => return Ok(()),
+    };
+
+    let mut entries = read_lockfile(lockfile)?;
+    let key = url.to_string();
+    let actual = source_code_sha256(source_code);
+
+    match entries.get(&key) {
+      Some(expected) if *expected == actual => Ok(()),
+      Some(expected) => Err(deno_error(format!(
+        "Subresource integrity check failed for {}\n  expected: {}\n  actual:   {}",
+        key, expected, actual
+      ))),
+      None if self.lock_write => {
+        entries.insert(key, actual);
+        write_lockfile(lockfile, &entries)
+      }
+      None => Err(deno_error(format!(
+        "No lockfile entry for {}; re-run with lock-write to record one",
+        key
+      ))),
+    }
+  }
+}
+
+fn deno_error(message: String) -> DenoError {
+  DenoError::from(std::io::Error::new(std::io::ErrorKind::InvalidData, message))
+}
+
+fn read_lockfile(path: &Path) -> DenoResult<HashMap<String, String>> {
+  match fs::read_to_string(path) {
+    Ok(contents) => serde_json::from_str(&contents)
+      .map_err(|e| deno_error(format!("Invalid lockfile {}: {}", path.display(), e))),
+    Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => Ok(HashMap::new()),
+    Err(err) => Err(err.into()),
+  }
 }
 
+fn write_lockfile(path: &Path, entries: &HashMap<String, String>) -> DenoResult<()> {
+  let serialized = serde_json::to_string_pretty(entries)
+    .map_err(|e| deno_error(format!("Failed to serialize lockfile: {}", e)))?;
+  write_atomic(path, serialized.as_bytes()).map_err(DenoError::from)
+}
+
+// Writes `data` to `path` by first writing to a uniquely-named temp file in
+// the same directory and then `fs::rename`-ing it into place. `fs::rename`
+// is atomic within a filesystem on both POSIX and Windows, so a concurrent
+// reader of `path` either sees the previous complete file or the new one,
+// never a partial write. This replaces the old exists()-then-write pattern,
+// which raced when two processes populated the same cache entry at once.
+fn write_atomic<P: AsRef<Path>>(path: P, data: &[u8]) -> std::io::Result<()> {
+  let path = path.as_ref();
+  let file_name = path
+    .file_name()
+    .and_then(|f| f.to_str())
+    .unwrap_or("deno");
+  let unique = TEMP_FILE_COUNTER.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+  let tmp_path =
+    path.with_file_name(format!(".{}.{}.{}.tmp", file_name, std::process::id(), unique));
+  fs::write(&tmp_path, data)?;
+  // `fs::rename` atomically replaces an existing `path` on both POSIX
+  // (`rename(2)`) and Windows (`MoveFileEx` with `REPLACE_EXISTING`), so a
+  // concurrent writer losing the race still leaves a complete file behind
+  // rather than surfacing an error here. Failures we do see -- e.g. another
+  // process holding `path` open on Windows -- leave `tmp_path` behind, but
+  // since we generate a unique name per call there's nothing to clean up on
+  // the success path.
+  match fs::rename(&tmp_path, path) {
+    Ok(()) => Ok(()),
+    Err(err) => Err(err),
+  }
+}
+
+static TEMP_FILE_COUNTER: std::sync::atomic::AtomicUsize =
+  std::sync::atomic::AtomicUsize::new(0);
+
 fn get_cache_filename(basedir: &Path, url: Uri) -> PathBuf {
   let mut out = basedir.to_path_buf();
   out.push(url.host().unwrap());
@@ -292,6 +550,174 @@ fn get_cache_filename(basedir: &Path, url: Uri) -> PathBuf {
   out
 }
 
+// Metadata recorded alongside each cached remote module, under
+// `<cache path>.metadata.json`. Lets a cache hit recover the original
+// request URL and final (post-redirect) URL without re-fetching, and
+// carries the validators and freshness lifetime needed to conditionally
+// revalidate instead of blindly trusting or discarding the cache.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CacheMetadata {
+  url: String,
+  redirect_to: String,
+  headers: HashMap<String, String>,
+  etag: Option<String>,
+  last_modified: Option<String>,
+  // Seconds from `fetched_at` after which the entry should be
+  // revalidated, taken from the response's `Cache-Control: max-age`.
+  // `None` means the response gave us no freshness lifetime, so the
+  // entry is treated as fresh forever (the pre-existing behavior).
+  max_age: Option<u64>,
+  fetched_at: u64,
+}
+
+impl CacheMetadata {
+  fn from_fetch(requested_url: &Uri, fetch_result: &net::FetchResult) -> CacheMetadata {
+    let headers = net::headers_to_map(&fetch_result.headers);
+    CacheMetadata {
+      url: requested_url.to_string(),
+      redirect_to: fetch_result.url.to_string(),
+      etag: headers.get("etag").cloned(),
+      last_modified: headers.get("last-modified").cloned(),
+      max_age: headers
+        .get("cache-control")
+        .and_then(|v| parse_max_age(v)),
+      fetched_at: now_unix(),
+      headers,
+    }
+  }
+
+  fn is_stale(self: &CacheMetadata) -> bool {
+    match self.max_age {
+      Some(max_age) => now_unix().saturating_sub(self.fetched_at) >= max_age,
+      None => false,
+    }
+  }
+
+  // A `304 Not Modified` response confirms the cached body is still
+  // current without resending it; only the freshness clock advances.
+  fn refreshed(self: &CacheMetadata) -> CacheMetadata {
+    CacheMetadata {
+      fetched_at: now_unix(),
+      ..self.clone()
+    }
+  }
+}
+
+// The URL a cached entry's content should be treated as having come from:
+// `metadata.redirect_to` when it parses, since that's the module's real
+// location after any redirect, or `requested_url` if its `redirect_to` is
+// somehow not a valid URL. Callers fall back to `requested_url` themselves
+// when the metadata can't be read at all.
+fn canonical_url(metadata: &CacheMetadata, requested_url: &Uri) -> Uri {
+  metadata
+    .redirect_to
+    .parse::<Uri>()
+    .unwrap_or_else(|_| requested_url.clone())
+}
+
+fn now_unix() -> u64 {
+  std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap()
+    .as_secs()
+}
+
+// Parses the `max-age` directive out of a `Cache-Control` header value,
+// e.g. `"public, max-age=3600"` -> `Some(3600)`.
+fn parse_max_age(cache_control: &str) -> Option<u64> {
+  cache_control.split(',').find_map(|directive| {
+    let directive = directive.trim();
+    if directive.starts_with("max-age=") {
+      directive["max-age=".len()..].parse::<u64>().ok()
+    } else {
+      None
+    }
+  })
+}
+
+fn metadata_path(cache_path: &Path) -> PathBuf {
+  let mut file_name = cache_path.as_os_str().to_os_string();
+  file_name.push(".metadata.json");
+  PathBuf::from(file_name)
+}
+
+fn write_metadata(path: &Path, metadata: &CacheMetadata) -> DenoResult<()> {
+  let serialized = serde_json::to_string_pretty(metadata)
+    .map_err(|e| deno_error(format!("Failed to serialize {}: {}", path.display(), e)))?;
+  write_atomic(path, serialized.as_bytes()).map_err(DenoError::from)
+}
+
+fn read_metadata(path: &Path) -> DenoResult<CacheMetadata> {
+  let contents = fs::read_to_string(path)?;
+  serde_json::from_str(&contents)
+    .map_err(|e| deno_error(format!("Invalid metadata {}: {}", path.display(), e)))
+}
+
+#[test]
+fn test_parse_max_age() {
+  assert_eq!(parse_max_age("public, max-age=3600"), Some(3600));
+  assert_eq!(parse_max_age("max-age=0"), Some(0));
+  assert_eq!(parse_max_age("no-cache"), None);
+  assert_eq!(parse_max_age(""), None);
+}
+
+#[test]
+fn test_cache_metadata_is_stale() {
+  let fresh = CacheMetadata {
+    url: "http://example.com/mod.js".to_string(),
+    redirect_to: "http://example.com/mod.js".to_string(),
+    headers: HashMap::new(),
+    etag: None,
+    last_modified: None,
+    max_age: Some(3600),
+    fetched_at: now_unix(),
+  };
+  assert!(!fresh.is_stale());
+
+  let stale = CacheMetadata {
+    fetched_at: now_unix() - 7200,
+    ..fresh.clone()
+  };
+  assert!(stale.is_stale());
+
+  let no_lifetime = CacheMetadata {
+    max_age: None,
+    fetched_at: 0,
+    ..fresh
+  };
+  assert!(!no_lifetime.is_stale());
+}
+
+#[test]
+fn test_metadata_roundtrip() {
+  let (temp_dir, _deno_dir) = test_setup();
+  let cache_path = temp_dir.path().join("deps/example.com/mod.js");
+  let metadata_file = metadata_path(&cache_path);
+  assert_eq!(
+    metadata_file,
+    temp_dir.path().join("deps/example.com/mod.js.metadata.json")
+  );
+
+  let mut headers = HashMap::new();
+  headers.insert("etag".to_string(), "abc123".to_string());
+  let metadata = CacheMetadata {
+    url: "http://example.com/mod.js".to_string(),
+    redirect_to: "https://example.com/mod.js".to_string(),
+    headers,
+    etag: Some("abc123".to_string()),
+    last_modified: None,
+    max_age: Some(3600),
+    fetched_at: 0,
+  };
+
+  fs::create_dir_all(metadata_file.parent().unwrap()).unwrap();
+  write_metadata(&metadata_file, &metadata).expect("write_metadata error");
+  let read_back = read_metadata(&metadata_file).expect("read_metadata error");
+  assert_eq!(read_back.url, metadata.url);
+  assert_eq!(read_back.redirect_to, metadata.redirect_to);
+  assert_eq!(read_back.headers.get("etag").unwrap(), "abc123");
+}
+
 #[test]
 fn test_get_cache_filename() {
   let url = "http://example.com:1234/path/to/file.ts"
@@ -317,7 +743,8 @@ pub struct CodeFetchOutput {
 pub fn test_setup() -> (TempDir, DenoDir) {
   let temp_dir = TempDir::new().expect("tempdir fail");
   let deno_dir =
-    DenoDir::new(false, Some(temp_dir.path())).expect("setup fail");
+    DenoDir::new(false, Some(temp_dir.path()), None, false, None, false)
+      .expect("setup fail");
   (temp_dir, deno_dir)
 }
 
@@ -350,6 +777,62 @@ fn test_code_cache() {
   assert_eq!(output_code, fs::read_to_string(&cache_path).unwrap());
 }
 
+#[test]
+fn test_write_atomic() {
+  let (temp_dir, _deno_dir) = test_setup();
+  let path = temp_dir.path().join("atomic.txt");
+
+  write_atomic(&path, b"first").expect("write_atomic error");
+  assert_eq!("first", fs::read_to_string(&path).unwrap());
+
+  // A second write to the same path clobbers the first, and no temp files
+  // should be left behind in the directory.
+  write_atomic(&path, b"second").expect("write_atomic error");
+  assert_eq!("second", fs::read_to_string(&path).unwrap());
+
+  let leftover_tmp_files = fs::read_dir(temp_dir.path())
+    .unwrap()
+    .filter_map(|e| e.ok())
+    .filter(|e| e.file_name().to_str().unwrap().ends_with(".tmp"))
+    .count();
+  assert_eq!(leftover_tmp_files, 0);
+}
+
+#[test]
+fn test_check_source_integrity() {
+  let temp_dir = TempDir::new().expect("tempdir fail");
+  let lockfile = temp_dir.path().join("deno.lock");
+  let url = "http://example.com/mod.ts".parse::<Uri>().unwrap();
+
+  // No lockfile entry yet: with lock_write, the digest is recorded.
+  let recorder =
+    DenoDir::new(false, Some(temp_dir.path()), Some(&lockfile), true, None, false)
+      .expect("setup fail");
+  recorder
+    .check_source_integrity(&url, "1+2")
+    .expect("should record a new digest");
+  let entries = read_lockfile(&lockfile).unwrap();
+  assert_eq!(
+    entries.get("http://example.com/mod.ts").unwrap(),
+    "829e4d66733e1268db3a611506004902305a493ba3c4b613b8d595f5dd6531f3"
+  );
+
+  // Matching content verifies cleanly without lock_write.
+  let verifier =
+    DenoDir::new(false, Some(temp_dir.path()), Some(&lockfile), false, None, false)
+      .expect("setup fail");
+  verifier
+    .check_source_integrity(&url, "1+2")
+    .expect("matching digest should verify");
+
+  // Tampered content is rejected.
+  assert!(verifier.check_source_integrity(&url, "1+3").is_err());
+
+  // An unrecorded URL without lock_write is rejected rather than recorded.
+  let other_url = "http://example.com/other.ts".parse::<Uri>().unwrap();
+  assert!(verifier.check_source_integrity(&other_url, "1+2").is_err());
+}
+
 // https://github.com/denoland/deno/blob/golang/deno_dir.go#L25-L30
 fn source_code_hash(filename: &str, source_code: &str) -> String {
   let mut ctx = ring::digest::Context::new(&ring::digest::SHA1);
@@ -364,6 +847,28 @@ fn source_code_hash(filename: &str, source_code: &str) -> String {
   out
 }
 
+// Digest used for subresource integrity checks against a lockfile. Kept
+// separate from `source_code_hash` (SHA1, used only as a cache key) since
+// integrity checking wants a digest over the content alone.
+fn source_code_sha256(source_code: &str) -> String {
+  let mut ctx = ring::digest::Context::new(&ring::digest::SHA256);
+  ctx.update(source_code.as_bytes());
+  let digest = ctx.finish();
+  let mut out = String::new();
+  for byte in digest.as_ref() {
+    write!(&mut out, "{:02x}", byte).unwrap();
+  }
+  out
+}
+
+#[test]
+fn test_source_code_sha256() {
+  assert_eq!(
+    "829e4d66733e1268db3a611506004902305a493ba3c4b613b8d595f5dd6531f3",
+    source_code_sha256("1+2")
+  );
+}
+
 #[test]
 fn test_source_code_hash() {
   assert_eq!(
@@ -501,6 +1006,121 @@ fn test_resolve_module() {
   }
 }
 
+#[test]
+fn test_import_map_resolve() {
+  let mut imports = HashMap::new();
+  imports.insert("std/http".to_string(), "https://deno.land/std/http/mod.ts".to_string());
+  imports.insert("std/".to_string(), "https://deno.land/std/".to_string());
+  let map = ImportMap { imports };
+
+  assert_eq!(
+    map.resolve("std/http"),
+    Some("https://deno.land/std/http/mod.ts".to_string())
+  );
+  assert_eq!(
+    map.resolve("std/testing/mod.ts"),
+    Some("https://deno.land/std/testing/mod.ts".to_string())
+  );
+  assert_eq!(map.resolve("./local.ts"), None);
+}
+
+#[test]
+fn test_resolve_module_with_import_map() {
+  let (temp_dir, _deno_dir) = test_setup();
+  let import_map_path = temp_dir.path().join("import_map.json");
+  fs::write(
+    &import_map_path,
+    r#"{ "imports": { "std/http": "https://deno.land/std/http/mod.ts" } }"#,
+  ).unwrap();
+
+  let deno_dir = DenoDir::new(
+    false,
+    Some(temp_dir.path()),
+    None,
+    false,
+    Some(&import_map_path),
+    false,
+  ).expect("setup fail");
+
+  let (module_name, _filename) = deno_dir
+    .resolve_module("std/http", "/some/containing/file.ts")
+    .unwrap();
+  assert_eq!(
+    module_name,
+    ModuleLocation::Url(
+      "https://deno.land/std/http/mod.ts".parse::<Uri>().unwrap()
+    )
+  );
+}
+
+#[test]
+fn test_cached_only_missing_is_error() {
+  let (temp_dir, _deno_dir) = test_setup();
+  let cached_only_dir =
+    DenoDir::new(false, Some(temp_dir.path()), None, false, None, true)
+      .expect("setup fail");
+
+  let url = "http://example.com/not_cached.ts".parse::<Uri>().unwrap();
+  let filename = get_cache_filename(&cached_only_dir.deps, url.clone());
+  let r = cached_only_dir
+    .fetch_remote_source(&url, filename.to_str().unwrap());
+  assert!(r.is_err());
+}
+
+#[test]
+fn test_cached_only_hit_uses_cache() {
+  let (temp_dir, _deno_dir) = test_setup();
+  let url = "http://example.com/already_cached.ts".parse::<Uri>().unwrap();
+  let filename = get_cache_filename(&temp_dir.path().join("deps"), url.clone());
+  fs::create_dir_all(filename.parent().unwrap()).unwrap();
+  fs::write(&filename, "cached source").unwrap();
+
+  let cached_only_dir =
+    DenoDir::new(false, Some(temp_dir.path()), None, false, None, true)
+      .expect("setup fail");
+  let (source, canonical_url) = cached_only_dir
+    .fetch_remote_source(&url, filename.to_str().unwrap())
+    .expect("should use the cache without hitting the network");
+  assert_eq!(source, "cached source");
+  assert_eq!(canonical_url, url);
+}
+
+#[test]
+fn test_fetch_remote_source_consults_redirect_metadata() {
+  let (temp_dir, _deno_dir) = test_setup();
+  let url = "http://example.com/old.ts".parse::<Uri>().unwrap();
+  let filename = get_cache_filename(&temp_dir.path().join("deps"), url.clone());
+  fs::create_dir_all(filename.parent().unwrap()).unwrap();
+  fs::write(&filename, "redirected source").unwrap();
+  write_metadata(
+    &metadata_path(&filename),
+    &CacheMetadata {
+      url: url.to_string(),
+      redirect_to: "https://example.com/new/mod.ts".to_string(),
+      headers: HashMap::new(),
+      etag: None,
+      last_modified: None,
+      max_age: None,
+      fetched_at: now_unix(),
+    },
+  )
+  .unwrap();
+
+  // cached_only forces a cache hit without any network access, so this
+  // exercises the sidecar consultation on its own.
+  let cached_only_dir =
+    DenoDir::new(false, Some(temp_dir.path()), None, false, None, true)
+      .expect("setup fail");
+  let (source, canonical_url) = cached_only_dir
+    .fetch_remote_source(&url, filename.to_str().unwrap())
+    .expect("cache hit");
+  assert_eq!(source, "redirected source");
+  assert_eq!(
+    canonical_url,
+    "https://example.com/new/mod.ts".parse::<Uri>().unwrap()
+  );
+}
+
 const ASSET_PREFIX: &str = "/$asset$/";
 
 fn is_remote(module_name: &ModuleLocation) -> bool {
@@ -0,0 +1,181 @@
+// Copyright 2018 the Deno authors. All rights reserved. MIT license.
+use errors::DenoError;
+use errors::DenoResult;
+use hyper::client::HttpConnector;
+use hyper::header::HeaderMap;
+use hyper::Client;
+use hyper::StatusCode;
+use hyper::Uri;
+use hyper_tls::HttpsConnector;
+use std::collections::HashMap;
+use tokio::runtime::Runtime;
+
+// Redirects are followed up to this many times before giving up, mirroring
+// common browser/curl behavior.
+const MAX_REDIRECTS: u8 = 10;
+
+#[derive(Debug)]
+pub struct FetchResult {
+  pub body: String,
+  // The URL the response actually came from, after following any
+  // redirects. Equal to the requested URL when there were none.
+  pub url: Uri,
+  pub headers: HeaderMap,
+  pub status: StatusCode,
+}
+
+// Conditional-GET validators sent with `If-None-Match` / `If-Modified-Since`
+// so a server can answer `304 Not Modified` without resending the body.
+#[derive(Debug, Default)]
+pub struct Validators {
+  pub etag: Option<String>,
+  pub last_modified: Option<String>,
+}
+
+// Performs a blocking HTTP(S) GET of `url`, transparently following up to
+// `MAX_REDIRECTS` redirects, and returns the final response body along
+// with the final URL and headers so callers can make caching decisions.
+pub fn fetch_sync_string(url: &Uri) -> DenoResult<FetchResult> {
+  fetch_sync_string_conditional(url, &Validators::default())
+}
+
+// Like `fetch_sync_string`, but attaches `If-None-Match`/`If-Modified-Since`
+// from `validators` when present. A server that agrees the cached copy is
+// still fresh answers `304 Not Modified` with an empty body; callers should
+// check `FetchResult.status` and keep using their cached body in that case.
+pub fn fetch_sync_string_conditional(
+  url: &Uri,
+  validators: &Validators,
+) -> DenoResult<FetchResult> {
+  let client = https_client()?;
+  let mut runtime = Runtime::new().map_err(fetch_error)?;
+  let mut current = url.clone();
+
+  for _ in 0..=MAX_REDIRECTS {
+    let mut req = hyper::Request::get(current.clone());
+    if let Some(ref etag) = validators.etag {
+      req.header(hyper::header::IF_NONE_MATCH, etag.as_str());
+    }
+    if let Some(ref last_modified) = validators.last_modified {
+      req.header(hyper::header::IF_MODIFIED_SINCE, last_modified.as_str());
+    }
+    let req = req
+      .body(hyper::Body::empty())
+      .map_err(fetch_error)?;
+
+    let resp = runtime.block_on(client.request(req)).map_err(fetch_error)?;
+    let status = resp.status();
+
+    // `304` is itself a `3xx` status, so it must be checked before the
+    // redirect-following branch below -- otherwise a conditional revalidation
+    // that comes back fresh falls into that branch, finds no `Location`
+    // header (304 responses don't carry one), and errors out instead of
+    // reusing the cached body.
+    let headers = resp.headers().clone();
+    if status == StatusCode::NOT_MODIFIED {
+      return Ok(FetchResult {
+        body: String::new(),
+        url: current,
+        headers,
+        status,
+      });
+    }
+
+    if is_redirect(status) {
+      let location = resp
+        .headers()
+        .get(hyper::header::LOCATION)
+        .and_then(|v| v.to_str().ok())
+        .ok_or_else(|| {
+          fetch_error(format!("redirect from {} had no Location header", current))
+        })?
+        .to_string();
+      current = resolve_redirect(&current, &location)?;
+      continue;
+    }
+
+    let body = runtime
+      .block_on(resp.into_body().concat2())
+      .map_err(fetch_error)?;
+    let body_string =
+      String::from_utf8(body.to_vec()).map_err(fetch_error)?;
+    return Ok(FetchResult {
+      body: body_string,
+      url: current,
+      headers,
+      status,
+    });
+  }
+
+  Err(fetch_error(format!("too many redirects fetching {}", url)))
+}
+
+// `StatusCode::is_redirection()` covers the whole `3xx` range, including
+// codes like `300`, `304`, and `305` that don't carry a `Location` header
+// and so would otherwise send us into the redirect branch only to error out.
+// Restrict following to the statuses that actually redirect.
+fn is_redirect(status: StatusCode) -> bool {
+  match status {
+    StatusCode::MOVED_PERMANENTLY
+    | StatusCode::FOUND
+    | StatusCode::SEE_OTHER
+    | StatusCode::TEMPORARY_REDIRECT
+    | StatusCode::PERMANENT_REDIRECT => true,
+    _ => false,
+  }
+}
+
+// `Location` headers are allowed to be relative to the redirecting URL, so
+// resolve against `base` when `location` has no scheme of its own. A
+// leading `/` is root-relative; anything else is relative to the
+// directory of `base`'s path, per RFC 3986 §5 (e.g. `Location: c.ts` from
+// `http://h/a/b.ts` resolves to `http://h/a/c.ts`, not `http://h/c.ts`).
+fn resolve_redirect(base: &Uri, location: &str) -> DenoResult<Uri> {
+  match location.parse::<Uri>() {
+    Ok(uri) if uri.scheme_part().is_some() => Ok(uri),
+    _ => {
+      let scheme = base.scheme_part().map(|s| s.as_str()).unwrap_or("http");
+      let authority = base.authority_part().map(|a| a.as_str()).unwrap_or("");
+      let path = if location.starts_with('/') {
+        location.to_string()
+      } else {
+        let base_path = base.path();
+        let dir = match base_path.rfind('/') {
+          Some(idx) => &base_path[..=idx],
+          None => "/",
+        };
+        format!("{}{}", dir, location)
+      };
+      let joined = format!("{}://{}{}", scheme, authority, path);
+      joined.parse::<Uri>().map_err(fetch_error)
+    }
+  }
+}
+
+// `Client::new()` only ever builds hyper's plain `HttpConnector`, which
+// cannot speak TLS, so a remote `https://` import -- the common case for
+// deno.land/unpkg-style dependencies -- would otherwise fail outright.
+fn https_client() -> DenoResult<Client<HttpsConnector<HttpConnector>>> {
+  let https = HttpsConnector::new(4).map_err(fetch_error)?;
+  Ok(Client::builder().build::<_, hyper::Body>(https))
+}
+
+fn fetch_error<E: std::fmt::Display>(err: E) -> DenoError {
+  DenoError::from(std::io::Error::new(
+    std::io::ErrorKind::Other,
+    err.to_string(),
+  ))
+}
+
+// Flattens a hyper `HeaderMap` into a plain string map, dropping any
+// header values that aren't valid UTF-8 visible ASCII. Used when
+// serializing fetch metadata to the on-disk cache sidecar.
+pub fn headers_to_map(headers: &HeaderMap) -> HashMap<String, String> {
+  let mut map = HashMap::new();
+  for (name, value) in headers.iter() {
+    if let Ok(v) = value.to_str() {
+      map.insert(name.as_str().to_string(), v.to_string());
+    }
+  }
+  map
+}